@@ -32,6 +32,12 @@
 //! in `~/.cargo`, and for generated code in `./target/debug/build`. It will
 //! have a single [`FileSet`] which unions the two sources.
 //!
+//! If the embedder would rather keep sources and generated code in separate
+//! [`FileSet`]s, it can register them with [`Vfs::union_file_sets`] instead
+//! and resolve `mod foo;`-style paths across the union with
+//! [`Vfs::resolve_path`], which finds the right union from the path's
+//! anchor automatically.
+//!
 //! [`set_file_contents`]: Vfs::set_file_contents
 //! [`take_changes`]: Vfs::take_changes
 //! [`FileSet`]: file_set::FileSet
@@ -43,7 +49,14 @@ pub mod loader;
 mod path_interner;
 mod vfs_path;
 
-use std::{fmt, mem};
+use std::{
+    collections::hash_map::{DefaultHasher, Entry},
+    fmt,
+    hash::{Hash, Hasher},
+    mem,
+};
+
+use rustc_hash::FxHashMap;
 
 use crate::path_interner::PathInterner;
 
@@ -59,13 +72,96 @@ pub use paths::{AbsPath, AbsPathBuf};
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct FileId(pub u32);
 
+/// Handle to a group of [`FileSet`](file_set::FileSet)s registered as one
+/// logical crate via [`Vfs::union_file_sets`], e.g. a crate whose sources
+/// live in `~/.cargo` with generated code mirrored into
+/// `./target/debug/build`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FileSetUnionId(u32);
+
+/// Controls whether [`Vfs`] retains the full bytes of every file, or only a
+/// content fingerprint once a batch of changes has been drained.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StorageMode {
+    /// Keep the full contents of every file in memory. This is the
+    /// historical behavior, and the default.
+    Full,
+    /// After [`take_changes`](Vfs::take_changes) drains a batch, evict the
+    /// raw bytes of every disk-backed file and keep only its content
+    /// fingerprint. Bytes are reloaded through `loader` the next time they're
+    /// needed. In-memory files (ones with no on-disk path) can't be reloaded,
+    /// so their bytes are never evicted.
+    Fingerprint,
+}
+
+impl Default for StorageMode {
+    fn default() -> StorageMode {
+        StorageMode::Full
+    }
+}
+
+/// A 128-bit fingerprint of a file's contents, used to detect genuine edits
+/// without retaining the bytes themselves.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct ContentHash(u128);
+
+impl ContentHash {
+    fn of(bytes: &[u8]) -> ContentHash {
+        // `DefaultHasher` always starts from the same fixed key, so two
+        // independent passes over `bytes` would walk near-identical
+        // internal state and correlate instead of giving 128 bits of
+        // coverage. Folding `lo` in *before* the content bytes perturbs the
+        // whole `hi` pass, not just its last step.
+        let mut lo_hasher = DefaultHasher::new();
+        bytes.hash(&mut lo_hasher);
+        let lo = lo_hasher.finish();
+
+        let mut hi_hasher = DefaultHasher::new();
+        lo.hash(&mut hi_hasher);
+        bytes.hash(&mut hi_hasher);
+        let hi = hi_hasher.finish();
+
+        ContentHash(((hi as u128) << 64) | lo as u128)
+    }
+}
+
 /// Storage for all files read by rust-analyzer.
 ///
 /// For more informations see the [crate-level](crate) documentation.
 pub struct Vfs {
     interner: PathInterner,
     data: Vec<Option<FileContents>>,
-    changes: Vec<ChangedFile>,
+    /// Content fingerprint of each file, kept around even after `data` is
+    /// evicted in [`StorageMode::Fingerprint`] so that `set_id_contents` can
+    /// still tell genuine edits from no-op writes.
+    fingerprints: Vec<Option<ContentHash>>,
+    /// Net change kind per `FileId` accumulated since the last
+    /// [`take_changes`](Vfs::take_changes), coalesced as changes come in so
+    /// that a create-then-modify (or similar) within one batch nets out to a
+    /// single [`ChangedFile`].
+    changes: FxHashMap<FileId, ChangeKind>,
+    /// `FileId`s in `changes`, in the order they were first touched this
+    /// batch, so [`take_changes`](Vfs::take_changes) can return them
+    /// deterministically.
+    changed_file_order: Vec<FileId>,
+    /// `FileId`s whose `data` entry was written since the last
+    /// [`take_changes`](Vfs::take_changes), in [`StorageMode::Fingerprint`]
+    /// only. Lets eviction touch just these files instead of scanning all of
+    /// `data` on every drain.
+    touched_since_drain: Vec<FileId>,
+    /// Editor buffers, shadowing `data` for the `FileId`s that have one. See
+    /// [`set_overlay`](Vfs::set_overlay).
+    overlays: FxHashMap<FileId, (Vec<u8>, ContentHash)>,
+    /// Groups of [`FileSet`](file_set::FileSet)s registered as unions via
+    /// [`union_file_sets`](Vfs::union_file_sets), indexed by
+    /// [`FileSetUnionId`]. Each entry is a snapshot, refreshed in place by
+    /// [`update_file_set_union`](Vfs::update_file_set_union).
+    file_set_unions: Vec<Vec<file_set::FileSet>>,
+    /// Reverse index from a `FileId` to the union it was last registered as
+    /// a member of, so [`resolve_path`](Vfs::resolve_path) can find a file's
+    /// union from its anchor alone.
+    file_id_to_union: FxHashMap<FileId, FileSetUnionId>,
+    storage_mode: StorageMode,
     pub loader: Box<dyn loader::Handle + Send + Sync>,
 }
 
@@ -101,6 +197,21 @@ pub enum ChangeKind {
     Delete,
 }
 
+/// Combines a pending change with a newly observed one into their net
+/// effect, or `None` if they cancel out (e.g. a file created then deleted
+/// within the same batch).
+fn coalesce(pending: ChangeKind, new: ChangeKind) -> Option<ChangeKind> {
+    use ChangeKind::*;
+    match (pending, new) {
+        (Create, Modify) => Some(Create),
+        (Create, Delete) => None,
+        (Modify, Delete) => Some(Delete),
+        (Delete, Create) => Some(Modify),
+        (Modify, Modify) => Some(Modify),
+        (_, new) => Some(new),
+    }
+}
+
 /// `None` means the file was deleted.
 struct FileContents(Option<Vec<u8>>);
 
@@ -113,7 +224,28 @@ impl FileContents {
 
 impl Vfs {
     pub fn new(loader: Box<dyn loader::Handle + Send + Sync>) -> Self {
-        Self { loader, interner: PathInterner::default(), data: vec![], changes: vec![] }
+        Self::new_with_storage_mode(loader, StorageMode::default())
+    }
+
+    /// Like [`new`](Vfs::new), but lets the caller opt into
+    /// [`StorageMode::Fingerprint`].
+    pub fn new_with_storage_mode(
+        loader: Box<dyn loader::Handle + Send + Sync>,
+        storage_mode: StorageMode,
+    ) -> Self {
+        Self {
+            loader,
+            interner: PathInterner::default(),
+            data: vec![],
+            fingerprints: vec![],
+            changes: FxHashMap::default(),
+            changed_file_order: vec![],
+            touched_since_drain: vec![],
+            overlays: FxHashMap::default(),
+            file_set_unions: vec![],
+            file_id_to_union: FxHashMap::default(),
+            storage_mode,
+        }
     }
 
     /// Number of files currently stored.
@@ -139,11 +271,33 @@ impl Vfs {
 
     /// File content corresponding to the given `file_id`.
     ///
+    /// If `file_id` has a live [overlay](Vfs::set_overlay), its contents win
+    /// over whatever is on disk. Otherwise, in [`StorageMode::Fingerprint`],
+    /// this reloads the bytes through `loader` if they were evicted after
+    /// the last [`take_changes`].
+    ///
+    /// That reload is synchronous disk IO, unlike every other read in this
+    /// `Vfs`, and only happens on the first call after an eviction -- callers
+    /// on a hot path (e.g. salsa queries) should be aware the cost of this
+    /// getter is no longer always O(1). It also opens a narrow drift window:
+    /// if the file changed on disk after its last recorded fingerprint but
+    /// before this reload runs, the reloaded bytes are trusted as-is and no
+    /// new [`ChangedFile`] is emitted for that drift. The one exception is a
+    /// file deleted on disk during that window: the reload notices the file
+    /// is gone, records a delete the same as [`set_id_contents`] would, and
+    /// then this call panics same as any other already-deleted file.
+    ///
     /// # Panics
     ///
     /// Panics if the id is not present in the `Vfs`, or if the corresponding file is
-    /// deleted.
+    /// deleted (including a deletion discovered by the reload above).
+    ///
+    /// [`take_changes`]: Vfs::take_changes
+    /// [`set_id_contents`]: Vfs::set_id_contents
     pub fn file_contents(&mut self, file_id: FileId) -> &[u8] {
+        if let Some((contents, _)) = self.overlays.get(&file_id) {
+            return contents;
+        }
         self.get(file_id).0.as_deref().unwrap()
     }
 
@@ -155,6 +309,73 @@ impl Vfs {
         })
     }
 
+    /// Declares that `sets` together make up one logical crate whose module
+    /// tree spans several directories -- for example sources checked out
+    /// under `~/.cargo` with generated code mirrored into
+    /// `./target/debug/build`. [`resolve_path`](Vfs::resolve_path) falls
+    /// back to the other members of the returned union when a file's own
+    /// [`FileSet`](file_set::FileSet) doesn't contain the target path.
+    ///
+    /// `sets` is a snapshot of the embedder's `FileSet`s at registration
+    /// time. If those sets later gain or lose files -- for instance once a
+    /// build script finishes writing into `./target/debug/build` -- call
+    /// [`update_file_set_union`](Vfs::update_file_set_union) with the
+    /// returned id to refresh it; registering again would mint a new id and
+    /// leak the stale one.
+    pub fn union_file_sets(&mut self, sets: Vec<file_set::FileSet>) -> FileSetUnionId {
+        let id = FileSetUnionId(self.file_set_unions.len() as u32);
+        self.file_set_unions.push(Vec::new());
+        self.update_file_set_union(id, sets);
+        id
+    }
+
+    /// Replaces the [`FileSet`](file_set::FileSet)s registered under `union`
+    /// in place, re-deriving [`resolve_path`](Vfs::resolve_path)'s
+    /// `FileId`-to-union lookup from `sets`. Unlike calling
+    /// [`union_file_sets`](Vfs::union_file_sets) again, this does not mint a
+    /// new [`FileSetUnionId`] or leave the previous snapshot behind.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `union` was not returned by [`union_file_sets`](Vfs::union_file_sets).
+    pub fn update_file_set_union(&mut self, union: FileSetUnionId, sets: Vec<file_set::FileSet>) {
+        // Only the union's previous members need dropping, not every
+        // `FileId` ever registered across every union.
+        for set in &self.file_set_unions[union.0 as usize] {
+            for file_id in set.iter() {
+                if self.file_id_to_union.get(&file_id) == Some(&union) {
+                    self.file_id_to_union.remove(&file_id);
+                }
+            }
+        }
+        for set in &sets {
+            for file_id in set.iter() {
+                self.file_id_to_union.insert(file_id, union);
+            }
+        }
+        self.file_set_unions[union.0 as usize] = sets;
+    }
+
+    /// Resolves `path` against `anchor_set`, falling back to the sibling
+    /// [`FileSet`](file_set::FileSet)s registered alongside `path.anchor`
+    /// via [`union_file_sets`](Vfs::union_file_sets) (if any) when
+    /// `anchor_set` doesn't contain the target. Which union to fall back to
+    /// is looked up from `path.anchor` itself, so callers don't need to
+    /// already track that mapping. This lets a `mod foo;` declared in one
+    /// set resolve to a `foo.rs` generated into a sibling set, without the
+    /// embedder having to pre-merge them.
+    pub fn resolve_path(
+        &self,
+        anchor_set: &file_set::FileSet,
+        path: AnchoredPath<'_>,
+    ) -> Option<FileId> {
+        if let Some(file_id) = anchor_set.resolve_path(path) {
+            return Some(file_id);
+        }
+        let union = *self.file_id_to_union.get(&path.anchor)?;
+        self.file_set_unions[union.0 as usize].iter().find_map(|set| set.resolve_path(path))
+    }
+
     /// Update the `path` with the given `contents`. `None` means the file was deleted.
     ///
     /// Returns `true` if the file was modified, and saves the [change](ChangedFile).
@@ -168,23 +389,64 @@ impl Vfs {
 
     /// Update the `id` with the given `contents`. `None` means the file was deleted.
     ///
+    /// This writes to the base (on-disk) layer. If `file_id` has a live
+    /// [overlay](Vfs::set_overlay), the update is recorded but the editor's
+    /// view -- and thus whether a change is observed -- is unaffected.
+    ///
     /// Returns `true` if the file was modified, and saves the [change](ChangedFile).
     ///
     /// # Panics
     ///
     /// Panics if no file is associated to `file_id`.
     pub fn set_id_contents(&mut self, file_id: FileId, contents: Option<Vec<u8>>) -> bool {
-        let change_kind = match (&self.get(file_id).0, &contents) {
-            (None, None) => return false,
-            (None, Some(_)) => ChangeKind::Create,
-            (Some(_), None) => ChangeKind::Delete,
-            (Some(old), Some(new)) if old == new => return false,
-            (Some(_), Some(_)) => ChangeKind::Modify,
-        };
+        let idx = file_id.0 as usize;
+        let old_effective_hash = self.effective_hash(file_id);
+        let new_hash = contents.as_deref().map(ContentHash::of);
 
-        self.data[file_id.0 as usize] = Some(FileContents(contents));
-        self.changes.push(ChangedFile { file_id, change_kind });
-        true
+        self.fingerprints[idx] = new_hash;
+        self.data[idx] = Some(FileContents(contents));
+        if self.storage_mode == StorageMode::Fingerprint {
+            self.touched_since_drain.push(file_id);
+        }
+
+        if self.overlays.contains_key(&file_id) {
+            return false;
+        }
+        self.record_change(file_id, old_effective_hash, new_hash)
+    }
+
+    /// Shadows `path`'s contents with an in-memory editor buffer, which wins
+    /// over the base (on-disk) layer until [cleared](Vfs::clear_overlay).
+    ///
+    /// Returns `true` if this changes the file's effective contents, and
+    /// saves the [change](ChangedFile).
+    ///
+    /// If the path does not currently exists in the `Vfs`, allocates a new
+    /// [`FileId`] for it.
+    pub fn set_overlay(&mut self, path: VfsPath, contents: Vec<u8>) -> bool {
+        let file_id = self.alloc_file_id(path);
+        let old_effective_hash = self.effective_hash(file_id);
+        let new_hash = ContentHash::of(&contents);
+        self.overlays.insert(file_id, (contents, new_hash));
+        self.record_change(file_id, old_effective_hash, Some(new_hash))
+    }
+
+    /// Removes `path`'s overlay, if any, re-exposing the base (on-disk)
+    /// layer.
+    ///
+    /// Returns `true` if this changes the file's effective contents, and
+    /// saves the [change](ChangedFile).
+    ///
+    /// If the path does not currently exists in the `Vfs`, allocates a new
+    /// [`FileId`] for it.
+    pub fn clear_overlay(&mut self, path: VfsPath) -> bool {
+        let file_id = self.alloc_file_id(path);
+        let old_effective_hash = self.effective_hash(file_id);
+        if self.overlays.remove(&file_id).is_none() {
+            return false;
+        }
+        let new_effective_hash = self.fingerprints[file_id.0 as usize];
+        self.record_change(file_id, old_effective_hash, new_effective_hash)
     }
 
     /// Returns `true` if the `Vfs` contains [changes](ChangedFile).
@@ -192,9 +454,76 @@ impl Vfs {
         !self.changes.is_empty()
     }
 
-    /// Drain and returns all the changes in the `Vfs`.
+    /// Drain and returns all the changes in the `Vfs`, at most one
+    /// [`ChangedFile`] per [`FileId`], in the order each `FileId` was first
+    /// touched since the previous call.
     pub fn take_changes(&mut self) -> Vec<ChangedFile> {
-        mem::take(&mut self.changes)
+        let mut changes = mem::take(&mut self.changes);
+        let order = mem::take(&mut self.changed_file_order);
+        let changes = order
+            .into_iter()
+            .filter_map(|file_id| {
+                changes.remove(&file_id).map(|change_kind| ChangedFile { file_id, change_kind })
+            })
+            .collect();
+        if self.storage_mode == StorageMode::Fingerprint {
+            // The fingerprint was already recorded in `set_id_contents`, so
+            // the bytes can be dropped until something needs them again.
+            // Only files backed by an on-disk path can be reloaded by
+            // `get_or_load`, so virtual files are left alone; and only
+            // `touched_since_drain` needs revisiting, not every `FileId` ever
+            // allocated.
+            for file_id in mem::take(&mut self.touched_since_drain) {
+                let idx = file_id.0 as usize;
+                let is_disk_backed = self.interner.lookup(file_id).as_path().is_some();
+                if is_disk_backed && matches!(self.data[idx], Some(FileContents(Some(_)))) {
+                    self.data[idx] = None;
+                }
+            }
+        }
+        changes
+    }
+
+    /// The fingerprint of what `file_id` currently looks like to the rest of
+    /// the world: its overlay if it has a live one, else its base layer.
+    fn effective_hash(&self, file_id: FileId) -> Option<ContentHash> {
+        match self.overlays.get(&file_id) {
+            Some((_, hash)) => Some(*hash),
+            None => self.fingerprints[file_id.0 as usize],
+        }
+    }
+
+    /// Diffs `old_hash` against `new_hash`, coalescing the result into
+    /// `changes`. Returns `true` if they differ.
+    fn record_change(
+        &mut self,
+        file_id: FileId,
+        old_hash: Option<ContentHash>,
+        new_hash: Option<ContentHash>,
+    ) -> bool {
+        let change_kind = match (old_hash, new_hash) {
+            (None, None) => return false,
+            (None, Some(_)) => ChangeKind::Create,
+            (Some(_), None) => ChangeKind::Delete,
+            (Some(old), Some(new)) if old == new => return false,
+            (Some(_), Some(_)) => ChangeKind::Modify,
+        };
+
+        match self.changes.entry(file_id) {
+            Entry::Occupied(mut entry) => match coalesce(*entry.get(), change_kind) {
+                Some(merged) => {
+                    entry.insert(merged);
+                }
+                None => {
+                    entry.remove();
+                }
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(change_kind);
+                self.changed_file_order.push(file_id);
+            }
+        }
+        true
     }
 
     /// Returns the id associated with `path`
@@ -209,6 +538,7 @@ impl Vfs {
         let idx = file_id.0 as usize;
         let len = self.data.len().max(idx + 1);
         self.data.resize_with(len, || Some(FileContents::deleted()));
+        self.fingerprints.resize_with(len, || None);
         file_id
     }
 
@@ -235,7 +565,29 @@ impl Vfs {
             let vfs_path = self.interner.lookup(file_id);
             let path = vfs_path.as_path().expect("tried to lazily load an in-memory file");
             let contents = self.loader.load_sync(path);
-            self.set_id_contents(file_id, contents);
+            if contents.is_none() {
+                // Unlike `StorageMode::Full`, which would still be holding the
+                // file's last-known bytes and never notice, this reload just
+                // discovered the file is gone from disk. Record it as a
+                // genuine delete -- same as `set_id_contents(file_id, None)`
+                // would -- instead of silently caching `FileContents(None)`
+                // and leaving `take_changes` none the wiser.
+                //
+                // `get_or_load` is only reached through `file_contents`, which already
+                // returns early for overlaid files before reaching here, so
+                // there's no overlay to preserve precedence over.
+                let old_hash = self.fingerprints[file_id.0 as usize];
+                self.fingerprints[file_id.0 as usize] = None;
+                self.record_change(file_id, old_hash, None);
+            }
+            // The fingerprint was already recorded before the bytes were
+            // evicted, so an unchanged reload is a cache refill, not a change
+            // -- go straight to `data` instead of `set_id_contents`, which
+            // would re-hash the bytes and record any on-disk drift as a change.
+            self.data[file_id.0 as usize] = Some(FileContents(contents));
+            if self.storage_mode == StorageMode::Fingerprint {
+                self.touched_since_drain.push(file_id);
+            }
         }
         // NOTE: this double-index is a limitation of the borrow checker, it can be removed with polonius
         self.data[file_id.0 as usize].as_mut().unwrap()
@@ -247,3 +599,261 @@ impl fmt::Debug for Vfs {
         f.debug_struct("Vfs").field("n_files", &self.data.len()).finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct FixtureLoader {
+        disk: FxHashMap<AbsPathBuf, Vec<u8>>,
+    }
+
+    impl loader::Handle for FixtureLoader {
+        fn spawn(_sender: loader::Sender) -> Self {
+            FixtureLoader::default()
+        }
+        fn set_config(&mut self, _config: loader::Config) {}
+        fn invalidate(&mut self, _path: AbsPathBuf) {}
+        fn load_sync(&mut self, path: &AbsPath) -> Option<Vec<u8>> {
+            self.disk.get(path).cloned()
+        }
+    }
+
+    fn fixture_vfs(storage_mode: StorageMode) -> Vfs {
+        Vfs::new_with_storage_mode(Box::new(FixtureLoader::default()), storage_mode)
+    }
+
+    fn virtual_path(path: &str) -> VfsPath {
+        VfsPath::new_virtual_path(path.to_string())
+    }
+
+    fn disk_path(path: &str) -> (VfsPath, AbsPathBuf) {
+        let abs = AbsPathBuf::assert(PathBuf::from(path));
+        (VfsPath::from(abs.clone()), abs)
+    }
+
+    #[test]
+    fn coalesces_create_then_modify_into_a_single_create() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+        let path = virtual_path("/foo.rs");
+
+        vfs.set_file_contents(path.clone(), Some(b"fn foo() {}".to_vec()));
+        vfs.set_file_contents(path, Some(b"fn foo() { 1 }".to_vec()));
+
+        let changes = vfs.take_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].change_kind, ChangeKind::Create);
+    }
+
+    #[test]
+    fn coalesces_create_then_delete_into_nothing() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+        let path = virtual_path("/foo.rs");
+
+        vfs.set_file_contents(path.clone(), Some(b"fn foo() {}".to_vec()));
+        vfs.set_file_contents(path, None);
+
+        assert!(vfs.take_changes().is_empty());
+    }
+
+    #[test]
+    fn overlay_shadows_base_and_clear_overlay_restores_it() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+        let path = virtual_path("/foo.rs");
+
+        vfs.set_file_contents(path.clone(), Some(b"base".to_vec()));
+        vfs.take_changes();
+
+        vfs.set_overlay(path.clone(), b"editor buffer".to_vec());
+        vfs.take_changes();
+        let file_id = vfs.file_id(&path).unwrap();
+        assert_eq!(vfs.file_contents(file_id), b"editor buffer");
+
+        // A disk-side update while the overlay is live doesn't clobber the
+        // editor's view, and isn't observed as a change.
+        vfs.set_file_contents(path.clone(), Some(b"base, updated".to_vec()));
+        assert_eq!(vfs.file_contents(file_id), b"editor buffer");
+        assert!(vfs.take_changes().is_empty());
+
+        vfs.clear_overlay(path);
+        assert_eq!(vfs.file_contents(file_id), b"base, updated");
+        let changes = vfs.take_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].change_kind, ChangeKind::Modify);
+    }
+
+    #[test]
+    fn clear_overlay_with_unchanged_base_records_no_change() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+        let path = virtual_path("/foo.rs");
+
+        vfs.set_file_contents(path.clone(), Some(b"base".to_vec()));
+        vfs.take_changes();
+
+        vfs.set_overlay(path.clone(), b"base".to_vec());
+        vfs.take_changes();
+
+        vfs.clear_overlay(path);
+        assert!(vfs.take_changes().is_empty());
+    }
+
+    #[test]
+    fn fingerprint_mode_evicts_and_reloads_unchanged_bytes() {
+        let (path, abs_path) = disk_path("/foo.rs");
+        let mut vfs = fixture_vfs(StorageMode::Fingerprint);
+        let mut disk = FxHashMap::default();
+        disk.insert(abs_path, b"fn foo() {}".to_vec());
+        vfs.loader = Box::new(FixtureLoader { disk });
+
+        vfs.set_file_contents(path.clone(), Some(b"fn foo() {}".to_vec()));
+        vfs.take_changes();
+
+        let file_id = vfs.file_id(&path).unwrap();
+        // Bytes were evicted after `take_changes`; this reloads through the
+        // loader, and since the disk content didn't change, no new
+        // `ChangedFile` is recorded for it.
+        assert_eq!(vfs.file_contents(file_id), b"fn foo() {}");
+        assert!(vfs.take_changes().is_empty());
+    }
+
+    #[test]
+    fn resolve_path_falls_back_to_unioned_file_set() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+
+        let anchor_id = vfs.alloc_file_id(virtual_path("/cargo/src/lib.rs"));
+        let generated_id = vfs.alloc_file_id(virtual_path("/target/debug/build/foo.rs"));
+
+        let mut anchor_set = file_set::FileSet::default();
+        anchor_set.insert(anchor_id, virtual_path("/cargo/src/lib.rs"));
+
+        // A second, separately-owned copy of the same set goes into the
+        // union; `Vfs::resolve_path` takes the caller's own set by
+        // reference and only consults the union as a fallback.
+        let mut anchor_set_in_union = file_set::FileSet::default();
+        anchor_set_in_union.insert(anchor_id, virtual_path("/cargo/src/lib.rs"));
+
+        let mut generated_set = file_set::FileSet::default();
+        generated_set.insert(generated_id, virtual_path("/target/debug/build/foo.rs"));
+
+        vfs.union_file_sets(vec![anchor_set_in_union, generated_set]);
+
+        assert_eq!(anchor_set.resolve_path(AnchoredPath::new(anchor_id, "foo.rs")), None);
+        // No union id to pass in: `resolve_path` looks the union up from the
+        // anchor `FileId` itself.
+        assert_eq!(
+            vfs.resolve_path(&anchor_set, AnchoredPath::new(anchor_id, "foo.rs")),
+            Some(generated_id)
+        );
+    }
+
+    #[test]
+    fn resolve_path_without_a_registered_union_falls_back_to_nothing() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+        let anchor_id = vfs.alloc_file_id(virtual_path("/cargo/src/lib.rs"));
+
+        let mut anchor_set = file_set::FileSet::default();
+        anchor_set.insert(anchor_id, virtual_path("/cargo/src/lib.rs"));
+
+        assert_eq!(
+            vfs.resolve_path(&anchor_set, AnchoredPath::new(anchor_id, "foo.rs")),
+            None
+        );
+    }
+
+    #[test]
+    fn update_file_set_union_picks_up_files_generated_after_registration() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+
+        let anchor_id = vfs.alloc_file_id(virtual_path("/cargo/src/lib.rs"));
+        let mut anchor_set = file_set::FileSet::default();
+        anchor_set.insert(anchor_id, virtual_path("/cargo/src/lib.rs"));
+
+        let mut anchor_set_in_union = file_set::FileSet::default();
+        anchor_set_in_union.insert(anchor_id, virtual_path("/cargo/src/lib.rs"));
+
+        // The build script hasn't run yet: no generated sibling to fall back to.
+        let union = vfs.union_file_sets(vec![anchor_set_in_union, file_set::FileSet::default()]);
+        assert_eq!(
+            vfs.resolve_path(&anchor_set, AnchoredPath::new(anchor_id, "foo.rs")),
+            None
+        );
+
+        // The build script has now run; the embedder refreshes the same
+        // union id rather than leaking it and minting a new one.
+        let generated_id = vfs.alloc_file_id(virtual_path("/target/debug/build/foo.rs"));
+        let mut anchor_set_in_union = file_set::FileSet::default();
+        anchor_set_in_union.insert(anchor_id, virtual_path("/cargo/src/lib.rs"));
+        let mut generated_set = file_set::FileSet::default();
+        generated_set.insert(generated_id, virtual_path("/target/debug/build/foo.rs"));
+        vfs.update_file_set_union(union, vec![anchor_set_in_union, generated_set]);
+
+        assert_eq!(
+            vfs.resolve_path(&anchor_set, AnchoredPath::new(anchor_id, "foo.rs")),
+            Some(generated_id)
+        );
+    }
+
+    #[test]
+    fn coalesces_modify_then_delete_into_a_single_delete() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+        let path = virtual_path("/foo.rs");
+
+        vfs.set_file_contents(path.clone(), Some(b"fn foo() {}".to_vec()));
+        vfs.take_changes();
+
+        vfs.set_file_contents(path.clone(), Some(b"fn foo() { 1 }".to_vec()));
+        vfs.set_file_contents(path, None);
+
+        let changes = vfs.take_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].change_kind, ChangeKind::Delete);
+    }
+
+    #[test]
+    fn coalesces_delete_then_create_into_a_single_modify() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+        let path = virtual_path("/foo.rs");
+
+        vfs.set_file_contents(path.clone(), Some(b"fn foo() {}".to_vec()));
+        vfs.take_changes();
+
+        vfs.set_file_contents(path.clone(), None);
+        vfs.set_file_contents(path, Some(b"fn foo() { 1 }".to_vec()));
+
+        let changes = vfs.take_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].change_kind, ChangeKind::Modify);
+    }
+
+    #[test]
+    fn take_changes_orders_files_by_first_touch_this_batch() {
+        let mut vfs = fixture_vfs(StorageMode::Full);
+        let first = virtual_path("/first.rs");
+        let second = virtual_path("/second.rs");
+        let third = virtual_path("/third.rs");
+        vfs.alloc_file_id(first.clone());
+        vfs.alloc_file_id(second.clone());
+        vfs.alloc_file_id(third.clone());
+
+        // Touched out of alphabetical/allocation order, and `second` is
+        // touched twice -- only its first touch should affect its position.
+        vfs.set_file_contents(second.clone(), Some(b"second".to_vec()));
+        vfs.set_file_contents(first.clone(), Some(b"first".to_vec()));
+        vfs.set_file_contents(third.clone(), Some(b"third".to_vec()));
+        vfs.set_file_contents(second, Some(b"second, updated".to_vec()));
+
+        let changes = vfs.take_changes();
+        let paths: Vec<_> = changes.iter().map(|c| vfs.file_path(c.file_id)).collect();
+        assert_eq!(
+            paths,
+            vec![
+                VfsPath::new_virtual_path("/second.rs".to_string()),
+                VfsPath::new_virtual_path("/first.rs".to_string()),
+                VfsPath::new_virtual_path("/third.rs".to_string()),
+            ]
+        );
+    }
+}